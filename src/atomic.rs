@@ -0,0 +1,21 @@
+//! Atomic backend selection.
+//!
+//! By default this re-exports `core::sync::atomic`. Two cfgs swap it out:
+//! - `loom`: routes through `loom`'s atomics so the unit tests in
+//!   `loom_tests` (run with `RUSTFLAGS="--cfg loom" cargo test --lib`) can
+//!   exercise interleavings of register/unregister/handle.
+//! - feature `portable-atomic`: routes through the `portable-atomic` crate,
+//!   which emulates CAS on targets without native atomic instructions (e.g.
+//!   `thumbv6m-none-eabi`).
+//!
+//! `loom` takes priority over `portable-atomic` since loom models are only
+//! ever run on the host, which always has native atomics.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+#[cfg(all(not(loom), feature = "portable-atomic"))]
+pub(crate) use portable_atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+#[cfg(all(not(loom), not(feature = "portable-atomic")))]
+pub(crate) use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
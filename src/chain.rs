@@ -0,0 +1,290 @@
+//! Per-slot storage for [`HandlerTable`](crate::HandlerTable): a lock-free,
+//! prepend-only stack of handlers ordered by priority at dispatch time.
+//!
+//! Each node is individually heap-allocated via `alloc`, so registering any
+//! handler at all requires a `#[global_allocator]` to be in scope.
+
+use crate::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use crate::Handler;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr;
+
+struct Node {
+    priority: i32,
+    handler: Handler,
+    next: *mut Node,
+}
+
+/// Names one specific installation made by [`Chain::push_if_empty`], so it
+/// can later be undone via [`Chain::retract`] without disturbing a
+/// different installation that may have since taken its place.
+pub(crate) struct Installed(*mut Node);
+
+/// One unlinked chain parked on the retired list, awaiting reclamation.
+///
+/// This indirection exists so retiring a chain never writes through any of
+/// its `Node`s: a concurrent `invoke_all` traversal may still be reading a
+/// just-unlinked chain's nodes (including its tail's `next`, which must keep
+/// reading as the traversal's own terminator), so splicing the retired list
+/// together has to happen entirely through fields of this wrapper instead.
+struct Retired {
+    chain: *mut Node,
+    next: *mut Retired,
+}
+
+/// A lock-free chain of `(priority, handler)` entries for a single slot.
+///
+/// `clear` can't free a removed chain's nodes the instant it unlinks them:
+/// an `invoke_all` traversal that already loaded the old head may still be
+/// walking it. Removed nodes are instead parked on a `retired` list and
+/// only actually freed once `readers` reads zero, i.e. once no traversal
+/// that could have observed them is still in flight.
+pub(crate) struct Chain {
+    head: AtomicPtr<Node>,
+    readers: AtomicUsize,
+    retired: AtomicPtr<Retired>,
+}
+
+/// Marks one in-flight `invoke_all` traversal, so `clear` knows it isn't yet
+/// safe to free a chain it just unlinked.
+struct ReaderGuard<'a> {
+    readers: &'a AtomicUsize,
+}
+
+impl<'a> ReaderGuard<'a> {
+    fn enter(readers: &'a AtomicUsize) -> Self {
+        readers.fetch_add(1, Ordering::SeqCst);
+        Self { readers }
+    }
+}
+
+impl Drop for ReaderGuard<'_> {
+    fn drop(&mut self) {
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Chain {
+    #[cfg(not(loom))]
+    pub(crate) const fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+            readers: AtomicUsize::new(0),
+            retired: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    // `loom`'s atomics have no `const fn` constructor, so this can't be
+    // `const` under `#[cfg(loom)]` either; loom models only ever run in a
+    // normal (non-`const`) context, so that's not a loss.
+    #[cfg(loom)]
+    pub(crate) fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+            readers: AtomicUsize::new(0),
+            retired: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Unconditionally prepends `handler` to the chain.
+    pub(crate) fn push(&self, priority: i32, handler: Handler) {
+        let node = Box::into_raw(Box::new(Node {
+            priority,
+            handler,
+            next: ptr::null_mut(),
+        }));
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            // Safety: `node` was just allocated and is not yet visible to
+            // any other thread, so writing its `next` field is exclusive.
+            unsafe {
+                (*node).next = head;
+            }
+            // SeqCst (not the usual AcqRel) so this store lands in the same
+            // total order as `clear`'s swap and `invoke_all`'s load: a CAS
+            // that merely release-stores the new head would let a later
+            // SeqCst load in `invoke_all` skip past it and still return this
+            // node after a subsequent `clear` has already retired and freed
+            // it, since coherence between two SeqCst operations only applies
+            // when *both* sides of the race are themselves SeqCst.
+            if self
+                .head
+                .compare_exchange(head, node, Ordering::SeqCst, Ordering::Acquire)
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Installs `handler` as the chain's sole entry iff it is currently
+    /// empty. Returns a token identifying this specific installation on
+    /// success, usable with [`retract`](Self::retract) to undo it; `None`
+    /// if the chain was already occupied.
+    pub(crate) fn push_if_empty(&self, priority: i32, handler: Handler) -> Option<Installed> {
+        let node = Box::into_raw(Box::new(Node {
+            priority,
+            handler,
+            next: ptr::null_mut(),
+        }));
+        // SeqCst for the same reason as `push`'s CAS; see its comment.
+        if self
+            .head
+            .compare_exchange(ptr::null_mut(), node, Ordering::SeqCst, Ordering::Acquire)
+            .is_ok()
+        {
+            Some(Installed(node))
+        } else {
+            // Safety: `node` was never published, so we still own it.
+            drop(unsafe { Box::from_raw(node) });
+            None
+        }
+    }
+
+    /// Undoes the installation named by `installed`, iff `head` still
+    /// points at exactly that node, i.e. nothing has pushed onto or
+    /// cleared the chain since. Returns `true` if the undo succeeded (the
+    /// slot is empty again and the node has been retired); `false` if
+    /// something else already changed `head`, in which case the installed
+    /// handler is left exactly as it was.
+    pub(crate) fn retract(&self, installed: Installed) -> bool {
+        // SeqCst for the same reason as `push`'s CAS; see its comment.
+        if self
+            .head
+            .compare_exchange(installed.0, ptr::null_mut(), Ordering::SeqCst, Ordering::Acquire)
+            .is_ok()
+        {
+            self.retire(installed.0);
+            self.reclaim_if_quiescent();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire).is_null()
+    }
+
+    /// Removes every node from the chain and returns the handler that was
+    /// at the head (the most recently prepended one), if any. The removed
+    /// nodes are only actually freed once no concurrent `invoke_all`
+    /// traversal can still be observing them; see [`Chain`]'s docs.
+    pub(crate) fn clear(&self) -> Option<Handler> {
+        // SeqCst (rather than the usual AcqRel) so this swap and
+        // `invoke_all`'s load of `head` share a single total order with
+        // the `readers` counter ops below: that's what lets
+        // `reclaim_if_quiescent` conclude "no in-flight traversal can see
+        // what I'm about to free" from a plain `readers == 0` check.
+        let node = self.head.swap(ptr::null_mut(), Ordering::SeqCst);
+        if node.is_null() {
+            // Nothing of ours to retire, but an earlier `clear` may have
+            // left nodes parked on `retired` because readers was nonzero
+            // at the time; give reclamation another chance now in case
+            // that count has since dropped to zero.
+            self.reclaim_if_quiescent();
+            return None;
+        }
+        // Safety: `node` is the chain this call exclusively unlinked from
+        // `head` via the swap above; reading its first node's handler
+        // before retiring it is fine, nothing else can reach it.
+        let first = unsafe { (*node).handler };
+        self.retire(node);
+        self.reclaim_if_quiescent();
+        Some(first)
+    }
+
+    /// Parks the chain headed by `chain_head` on the retired list, wrapped
+    /// in a freshly allocated [`Retired`] so that no field of `chain_head`
+    /// itself (still possibly visible to an in-flight `invoke_all`) is
+    /// touched.
+    fn retire(&self, chain_head: *mut Node) {
+        let retired_node = Box::into_raw(Box::new(Retired {
+            chain: chain_head,
+            next: ptr::null_mut(),
+        }));
+        loop {
+            let retired_head = self.retired.load(Ordering::Acquire);
+            // Safety: `retired_node` was just allocated and is not yet
+            // visible to any other thread, so writing its `next` field is
+            // exclusive.
+            unsafe {
+                (*retired_node).next = retired_head;
+            }
+            if self
+                .retired
+                .compare_exchange(retired_head, retired_node, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Frees the retired list, but only if `readers` is currently zero: a
+    /// nonzero count means some `invoke_all` call may have loaded the old
+    /// `head` before it was retired and could still be walking it, so
+    /// freeing now would be a use-after-free. If reclamation is skipped
+    /// here, the nodes stay parked until a later `clear` (or `Drop`) finds
+    /// the table quiescent.
+    fn reclaim_if_quiescent(&self) {
+        if self.readers.load(Ordering::SeqCst) != 0 {
+            return;
+        }
+        let mut retired = self.retired.swap(ptr::null_mut(), Ordering::AcqRel);
+        while !retired.is_null() {
+            // Safety: `readers == 0` was just observed, and every chain
+            // reachable from this retired list was unlinked from `head`
+            // before being retired, so no in-flight `invoke_all` traversal
+            // can be dereferencing it.
+            let boxed_retired = unsafe { Box::from_raw(retired) };
+            let mut node = boxed_retired.chain;
+            while !node.is_null() {
+                let boxed_node = unsafe { Box::from_raw(node) };
+                node = boxed_node.next;
+            }
+            retired = boxed_retired.next;
+        }
+    }
+
+    /// Invokes every handler currently in the chain, highest priority
+    /// first, returning how many ran.
+    ///
+    /// # Concurrency
+    /// Safe to call concurrently with `push` (prepending never mutates an
+    /// already-linked node) and with `clear` (removed nodes are reclaimed
+    /// only once no `invoke_all` traversal can still observe them).
+    pub(crate) fn invoke_all(&self) -> usize {
+        let _guard = ReaderGuard::enter(&self.readers);
+        let mut entries = Vec::new();
+        // SeqCst to match `clear`'s swap; see the comment there.
+        let mut node = self.head.load(Ordering::SeqCst);
+        while !node.is_null() {
+            // Safety: nodes reachable from `head` at the time of the load
+            // above are kept alive at least until this traversal's
+            // `ReaderGuard` drops, by `reclaim_if_quiescent`'s contract.
+            let n = unsafe { &*node };
+            entries.push((n.priority, n.handler));
+            node = n.next;
+        }
+        // Traversal walks from `head`, i.e. most-recently-pushed first, so
+        // `entries` is in reverse registration order; reverse it back before
+        // the stable sort so same-priority handlers tie-break in the order
+        // they were registered, as documented on `HandlerTable::register_handler_with_priority`.
+        entries.reverse();
+        entries.sort_by_key(|(priority, _)| core::cmp::Reverse(*priority));
+        for (_, handler) in &entries {
+            handler();
+        }
+        entries.len()
+    }
+}
+
+impl Drop for Chain {
+    fn drop(&mut self) {
+        // `&mut self` means no concurrent readers exist, so `clear`'s
+        // quiescence check always succeeds and frees everything here.
+        self.clear();
+    }
+}
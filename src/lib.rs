@@ -1,29 +1,267 @@
 #![no_std]
 #![doc = include_str!("../README.md")]
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+extern crate alloc;
+
+mod atomic;
+mod chain;
+#[cfg(all(test, loom))]
+mod loom_tests;
+
+use atomic::{AtomicUsize, Ordering};
+use chain::Chain;
 
 /// The type of an event handler.
 ///
 /// Currently, only no arguments and return values are supported.
 pub type Handler = fn();
 
+/// Number of bits of a [`Handle`] given to the slot index; the remaining
+/// high bits hold the generation counter.
+const HANDLE_INDEX_BITS: u32 = u32::BITS;
+
+/// An opaque token returned by [`HandlerTable::register_checked`].
+///
+/// A `Handle` packs the slot index (low bits) and the generation the slot
+/// was in at registration time (high bits) into a single `u64`. Pass it to
+/// [`HandlerTable::handle_token`] to safely invoke the handler, even if the
+/// caller holds on to the handle across intervening unregister/register
+/// cycles: the generation check rejects a handle whose slot has since been
+/// reused.
+///
+/// # Wrapping
+/// The generation counter is `64 - HANDLE_INDEX_BITS` bits wide and wraps on
+/// overflow. A wrapped-around generation is the only way a stale `Handle`
+/// can collide with a newer registration in the same slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handle(u64);
+
+impl Handle {
+    fn new(idx: usize, generation: usize) -> Self {
+        let idx = idx as u64 & ((1u64 << HANDLE_INDEX_BITS) - 1);
+        Self(((generation as u64) << HANDLE_INDEX_BITS) | idx)
+    }
+
+    fn index(self) -> usize {
+        (self.0 & ((1u64 << HANDLE_INDEX_BITS) - 1)) as usize
+    }
+
+    fn generation(self) -> usize {
+        (self.0 >> HANDLE_INDEX_BITS) as usize
+    }
+}
+
+/// Number of low bits of a free-list entry (`free_head`/`free_next`) given
+/// to the slot index; the remaining high bits hold a monotonic tag. Split
+/// evenly rather than fixed like [`HANDLE_INDEX_BITS`], since a free-list
+/// entry is a `usize` (whatever width the target's pointer is) rather than
+/// always a `u64`.
+const FREE_INDEX_BITS: u32 = usize::BITS / 2;
+
+/// Packs `idx_plus_one` (`0` meaning "no next"/"empty") into the low
+/// `FREE_INDEX_BITS` bits of a free-list entry, with `tag` in the high
+/// bits.
+///
+/// The tag exists purely to defeat ABA on the free list's CAS loop: without
+/// it, a slot popped and re-pushed between another thread's load and CAS of
+/// `free_head` would look unchanged (same `idx_plus_one`), letting that CAS
+/// succeed against a list whose actual linkage has since changed. Stamping
+/// each push with a fresh tag (see [`HandlerTable::push_free`]) means a
+/// racing CAS sees a different encoded value and retries instead.
+fn encode_free(idx_plus_one: usize, tag: usize) -> usize {
+    (tag << FREE_INDEX_BITS) | (idx_plus_one & ((1usize << FREE_INDEX_BITS) - 1))
+}
+
+/// Extracts the `idx + 1` portion of a free-list entry (`0` means empty).
+fn decode_free_index(entry: usize) -> usize {
+    entry & ((1usize << FREE_INDEX_BITS) - 1)
+}
+
 /// A lock-free table of event handlers.
 ///
-/// Internally stores up to `N` function pointers in an array of `AtomicUsize`.
-/// All operations are O(1), and are safe for concurrent use in `no_std`.
+/// Internally stores up to `N` slots, each a priority-ordered chain of
+/// handlers. All operations are O(1) (dispatch is O(k) in the number of
+/// handlers registered on that slot), and are safe for concurrent use in
+/// `no_std`.
 ///
 /// # Type Parameters
 /// - `N`: Number of handler slots (must be > 0).
 pub struct HandlerTable<const N: usize> {
-    handlers: [AtomicUsize; N],
+    chains: [Chain; N],
+    generations: [AtomicUsize; N],
+    /// Bump cursor over slots never yet touched by [`insert`](Self::insert).
+    next_slot: AtomicUsize,
+    /// Head of a lock-free free list threaded through `free_next`, used to
+    /// recycle slots freed by [`unregister_handler`](Self::unregister_handler).
+    /// Packs `idx + 1` (`0` meaning the list is empty) in the low
+    /// [`FREE_INDEX_BITS`] bits, tagged in the high bits; see
+    /// [`encode_free`].
+    free_head: AtomicUsize,
+    /// `free_next[idx]` holds the next free list entry (same encoding as
+    /// `free_head`) for a slot that is currently on the free list.
+    free_next: [AtomicUsize; N],
+    /// Monotonic counter stamped into every entry pushed onto the free
+    /// list, so that re-freeing the same `idx` is never indistinguishable
+    /// from its last time on the list; see [`encode_free`].
+    free_tag: AtomicUsize,
+    /// `1` while slot `idx` is linked (or being linked) into the free list,
+    /// `0` otherwise; arbitrates concurrent [`push_free`](Self::push_free)
+    /// calls for the *same* slot.
+    ///
+    /// A slot can go vacant more than once before anything pops it back
+    /// out: `unregister_handler(idx)` leaves a vacancy and starts pushing
+    /// it, but a direct `register_handler(idx, _)` can re-occupy the slot
+    /// (and a second `unregister_handler(idx)` re-vacate it) before that
+    /// first push lands. Since `free_next[idx]` is a single cell, two
+    /// pushes for the same `idx` racing on it can splice `idx` into a
+    /// cycle with itself rather than two distinct list entries. This flag
+    /// makes the first push to claim it (`0 -> 1`) the only one that
+    /// actually links `idx` in; any later push_free call for the same
+    /// `idx` that finds it already claimed just returns, trusting the
+    /// first to finish -- the free list only needs to know "`idx` is
+    /// vacant" once, not once per vacancy.
+    free_linked: [AtomicUsize; N],
 }
 
 impl<const N: usize> HandlerTable<N> {
     /// Creates a new `HandlerTable` with all slots empty.
+    ///
+    /// # Panics (compile-time)
+    /// `N` must fit in [`FREE_INDEX_BITS`] bits; past that, `encode_free`
+    /// would silently truncate a slot's index, letting the free list
+    /// confuse two different slots. This is only reachable on 32-bit
+    /// targets with `N >= 65536`; on 64-bit targets it's unreachable in
+    /// practice.
+    #[cfg(not(loom))]
     pub const fn new() -> Self {
+        const {
+            assert!(
+                N < (1usize << FREE_INDEX_BITS),
+                "HandlerTable: N does not fit in the free list's index encoding"
+            )
+        };
+        Self {
+            chains: [const { Chain::new() }; N],
+            generations: [const { AtomicUsize::new(0) }; N],
+            next_slot: AtomicUsize::new(0),
+            free_head: AtomicUsize::new(0),
+            free_next: [const { AtomicUsize::new(0) }; N],
+            free_tag: AtomicUsize::new(0),
+            free_linked: [const { AtomicUsize::new(0) }; N],
+        }
+    }
+
+    /// Creates a new `HandlerTable` with all slots empty.
+    ///
+    /// `loom`'s atomics have no `const fn` constructor, so under the `loom`
+    /// cfg this can't be `const` either; loom models always run in a normal
+    /// (non-`const`) context, so that's not a loss.
+    ///
+    /// # Panics (compile-time)
+    /// Same `N` bound as the non-`loom` constructor above.
+    #[cfg(loom)]
+    pub fn new() -> Self {
+        const {
+            assert!(
+                N < (1usize << FREE_INDEX_BITS),
+                "HandlerTable: N does not fit in the free list's index encoding"
+            )
+        };
         Self {
-            handlers: [const { AtomicUsize::new(0) }; N],
+            chains: core::array::from_fn(|_| Chain::new()),
+            generations: core::array::from_fn(|_| AtomicUsize::new(0)),
+            next_slot: AtomicUsize::new(0),
+            free_head: AtomicUsize::new(0),
+            free_next: core::array::from_fn(|_| AtomicUsize::new(0)),
+            free_tag: AtomicUsize::new(0),
+            free_linked: core::array::from_fn(|_| AtomicUsize::new(0)),
+        }
+    }
+
+    /// Claims the first free slot and registers `handler` there, mirroring
+    /// `sharded-slab`'s `insert`: callers don't pick an index, the table
+    /// does.
+    ///
+    /// Freed slots (from [`unregister_handler`](Self::unregister_handler))
+    /// are recycled first, via a lock-free free list; slots never used
+    /// before that are handed out by a bump cursor. Both paths are O(1)
+    /// amortized: a slot can be skipped (and retried) if something else
+    /// registered into it directly via [`register_handler`](Self::register_handler)
+    /// in the meantime, since `insert` shares its index space with that API.
+    ///
+    /// # Returns
+    /// - `Some(idx)` naming the claimed slot.
+    /// - `None` if the table is full.
+    pub fn insert(&self, handler: Handler) -> Option<usize> {
+        loop {
+            let head = self.free_head.load(Ordering::Acquire);
+            let idx_plus_one = decode_free_index(head);
+            if idx_plus_one != 0 {
+                let idx = idx_plus_one - 1;
+                let next = self.free_next[idx].load(Ordering::Acquire);
+                if self
+                    .free_head
+                    .compare_exchange(head, next, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    // `idx` is off the free list now; let a future
+                    // `unregister_handler(idx)` push it again.
+                    self.free_linked[idx].store(0, Ordering::Release);
+                    if self.chains[idx].push_if_empty(0, handler).is_some() {
+                        return Some(idx);
+                    }
+                    // Something else (e.g. a direct `register_handler`)
+                    // claimed this slot between it being freed and being
+                    // popped here; try again rather than reporting success
+                    // for a slot `handler` was never actually installed in.
+                    continue;
+                }
+                continue;
+            }
+
+            let idx = self.next_slot.fetch_add(1, Ordering::AcqRel);
+            if idx >= N {
+                return None;
+            }
+            if self.chains[idx].push_if_empty(0, handler).is_some() {
+                return Some(idx);
+            }
+            // `idx` was already claimed directly via `register_handler`
+            // before the bump cursor reached it; move on to the next slot.
+        }
+    }
+
+    /// Pushes `idx` onto the free list so a future [`insert`](Self::insert)
+    /// can recycle it.
+    ///
+    /// No-ops if `idx` is already linked (or concurrently being linked)
+    /// into the free list: see the `free_linked` field doc for why a
+    /// second push for the same slot must not touch `free_next[idx]`/
+    /// `free_head` itself.
+    fn push_free(&self, idx: usize) {
+        if self.free_linked[idx]
+            .compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return;
+        }
+        // One fresh tag for this push, not re-fetched on each CAS retry
+        // below: it only needs to be different from whatever tag `idx` last
+        // carried onto the list, not globally unique, and a relaxed counter
+        // is enough for that (the free-list CAS itself still provides the
+        // real ordering).
+        let tag = self.free_tag.fetch_add(1, Ordering::Relaxed);
+        let new_head = encode_free(idx + 1, tag);
+        loop {
+            let head = self.free_head.load(Ordering::Acquire);
+            self.free_next[idx].store(head, Ordering::Release);
+            if self
+                .free_head
+                .compare_exchange(head, new_head, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return;
+            }
         }
     }
 
@@ -35,13 +273,79 @@ impl<const N: usize> HandlerTable<N> {
     /// # Returns
     /// - `true` if the slot was empty and registration succeeded.
     /// - `false` if `idx` is out of range or the slot was already occupied.
+    ///
+    /// This is a thin wrapper over
+    /// [`register_handler_with_priority`](Self::register_handler_with_priority)
+    /// that only claims an empty slot; use that method directly to fan a
+    /// slot out to more than one handler.
     pub fn register_handler(&self, idx: usize, handler: Handler) -> bool {
         if idx >= N {
             return false;
         }
-        self.handlers[idx]
-            .compare_exchange(0, handler as usize, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
+        self.chains[idx].push_if_empty(0, handler).is_some()
+    }
+
+    /// Registers `handler` in slot `idx` alongside any handlers already
+    /// there, to be invoked by [`handle`](Self::handle) in descending
+    /// `priority` order (ties keep registration order).
+    ///
+    /// Unlike [`register_handler`](Self::register_handler), this always
+    /// succeeds as long as `idx` is in range: a slot can hold any number of
+    /// handlers.
+    ///
+    /// # Returns
+    /// - `true` if `idx` is in range.
+    /// - `false` if `idx` is out of range.
+    pub fn register_handler_with_priority(
+        &self,
+        idx: usize,
+        priority: i32,
+        handler: Handler,
+    ) -> bool {
+        if idx >= N {
+            return false;
+        }
+        self.chains[idx].push(priority, handler);
+        true
+    }
+
+    /// Attempts to register `handler` in slot `idx`, returning a [`Handle`]
+    /// that can later be passed to [`handle_token`](Self::handle_token) to
+    /// safely invoke it even after intervening register/unregister cycles
+    /// elsewhere in the table.
+    ///
+    /// # Returns
+    /// - `Some(handle)` if the slot was empty and registration succeeded.
+    /// - `None` if `idx` is out of range or the slot was already occupied.
+    ///
+    /// # Concurrency
+    /// Neither snapshotting the generation before the registration CAS nor
+    /// after it is race-free on its own: read too early and a concurrent
+    /// `unregister_handler(idx)` racing the CAS can bump the generation
+    /// without this call observing it, handing back a `Handle` that's
+    /// already stale the moment it's returned; read too late and that same
+    /// race can instead bump the generation *before* this call observes it,
+    /// handing back a `Handle` that names the *next* occupant's generation
+    /// rather than the one this call's own handler was installed under.
+    /// Reading the generation both before and after, and requiring them to
+    /// agree, catches either direction: on a mismatch, this call can't
+    /// trust either reading, so it retracts its own installation (a no-op
+    /// if something else has already displaced it) and retries from
+    /// scratch rather than risk returning a `Handle` for the wrong
+    /// generation.
+    pub fn register_checked(&self, idx: usize, handler: Handler) -> Option<Handle> {
+        if idx >= N {
+            return None;
+        }
+        loop {
+            let before = self.generations[idx].load(Ordering::Acquire);
+            let installed = self.chains[idx].push_if_empty(0, handler)?;
+            let after = self.generations[idx].load(Ordering::Acquire);
+            if before == after {
+                return Some(Handle::new(idx, before));
+            }
+            self.chains[idx].retract(installed);
+        }
     }
 
     /// Unregisters and returns the handler in slot `idx`.
@@ -54,45 +358,107 @@ impl<const N: usize> HandlerTable<N> {
     /// - `None` if `idx` is out of range or the slot was empty.
     ///
     /// # Concurrency
-    /// Lock-free and thread-safe: uses atomic swap.
+    /// Lock-free and thread-safe: uses atomic swap. Bumps the slot's
+    /// generation counter before clearing it (rather than after), so that
+    /// any concurrent [`register_checked`](Self::register_checked) call
+    /// that manages to install into the slot this call is vacating can
+    /// never observe the slot as empty without also observing the bump;
+    /// see that method's docs. The bump happens on every call, even one
+    /// that finds the slot already empty, to keep this ordering simple.
     pub fn unregister_handler(&self, idx: usize) -> Option<Handler> {
         if idx >= N {
             return None;
         }
-        let handler = self.handlers[idx].swap(0, Ordering::Acquire);
-        if handler != 0 {
-            Some(unsafe { core::mem::transmute::<usize, fn()>(handler) })
-        } else {
-            None
+        self.generations[idx].fetch_add(1, Ordering::AcqRel);
+        let handler = self.chains[idx].clear();
+        if handler.is_some() {
+            self.push_free(idx);
         }
+        handler
     }
 
-    /// Invokes the handler in slot `idx`.
+    /// Invokes the handler referenced by `handle`, iff its slot is still in
+    /// the generation the handle was issued for.
+    ///
+    /// # Returns
+    /// - `true` if the handle was still valid and its handler ran.
+    /// - `false` if the slot has since been unregistered (and possibly
+    ///   reused), or the handle's index is out of range.
+    ///
+    /// # Panics
+    /// Panics if the handler itself panics.
+    pub fn handle_token(&self, handle: Handle) -> bool {
+        let idx = handle.index();
+        if idx >= N {
+            return false;
+        }
+        if self.generations[idx].load(Ordering::Acquire) != handle.generation() {
+            return false;
+        }
+        self.handle(idx)
+    }
+
+    /// Invokes every handler registered in slot `idx`, highest priority
+    /// first (handlers registered via the plain [`register_handler`]
+    /// co-exist with those added through
+    /// [`register_handler_with_priority`](Self::register_handler_with_priority)
+    /// as priority `0`).
+    ///
+    /// [`register_handler`]: Self::register_handler
     ///
     /// # Parameters
     /// - `idx`: Slot index (0 ≤ `idx` < `N`).
     ///
     /// # Returns
-    /// - `true` if a handler was found and called.
+    /// - `true` if at least one handler was found and called.
     /// - `false` if `idx` is out of range or the slot was empty.
     ///
     /// # Concurrency
-    /// Lock-free and thread-safe: uses atomic load.
+    /// Lock-free and thread-safe: uses atomic loads.
     ///
     /// # Panics
-    /// Panics if the handler itself panics.
+    /// Panics if a handler itself panics.
     pub fn handle(&self, idx: usize) -> bool {
         if idx >= N {
             return false;
         }
-        let handler = self.handlers[idx].load(Ordering::Acquire);
-        if handler != 0 {
-            let handler: Handler = unsafe { core::mem::transmute(handler) };
-            handler();
-            true
-        } else {
-            false
-        }
+        self.chains[idx].invoke_all() > 0
+    }
+
+    /// Reports whether slot `idx` currently has at least one handler
+    /// registered.
+    ///
+    /// Unlike tokio's ready-bitmap, this doesn't track occupancy in a
+    /// separate `AtomicUsize` word: each slot's chain head already encodes
+    /// "empty or not" atomically, so a standalone bitmap would just be
+    /// redundant state to keep in sync. `is_occupied` reads that existing
+    /// state directly.
+    ///
+    /// # Returns
+    /// - `false` if `idx` is out of range or the slot is empty.
+    pub fn is_occupied(&self, idx: usize) -> bool {
+        idx < N && !self.chains[idx].is_empty()
+    }
+
+    /// Counts how many of the `N` slots currently have at least one handler
+    /// registered.
+    pub fn occupied(&self) -> usize {
+        (0..N).filter(|&idx| self.is_occupied(idx)).count()
+    }
+
+    /// Invokes every handler in every occupied slot, skipping empty slots
+    /// entirely rather than probing each index with [`handle`](Self::handle).
+    ///
+    /// # Returns
+    /// The total number of handlers that ran, across all slots.
+    ///
+    /// # Panics
+    /// Panics if any handler itself panics.
+    pub fn handle_all(&self) -> usize {
+        (0..N)
+            .filter(|&idx| self.is_occupied(idx))
+            .map(|idx| self.chains[idx].invoke_all())
+            .sum()
     }
 }
 
@@ -0,0 +1,334 @@
+//! Concurrency tests run under `loom` to exhaustively explore thread
+//! interleavings of `register_handler`/`register_handler_with_priority`,
+//! `unregister_handler`, `insert`, and `handle`/`handle_all` racing on the
+//! same table.
+//!
+//! These live here, gated as unit tests, rather than in `tests/`: an
+//! integration test links against the plain (non-`#[cfg(test)]`) library
+//! build, which has no access to dev-dependencies, so a `loom` import there
+//! fails to compile no matter what `--cfg` is passed. Unit tests compile as
+//! part of the crate's own `#[cfg(test)]` build, which does see
+//! dev-dependencies.
+//!
+//! Run with:
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --lib
+//! ```
+
+use crate::HandlerTable;
+// `loom`'s `AtomicUsize` has no `const fn` constructor, so the `static`
+// test-oracle counters below use the real `core` atomics instead; that's
+// fine since they only record an outcome for the assertions after `join`,
+// they aren't part of what's under test. `Ordering` is the same type either
+// way (loom re-exports `core`'s).
+use core::sync::atomic::{AtomicUsize, Ordering};
+use loom::sync::Arc;
+use loom::thread;
+
+static RAN: AtomicUsize = AtomicUsize::new(0);
+
+fn handler() {
+    RAN.fetch_add(1, Ordering::SeqCst);
+}
+
+fn handler_a() {
+    RAN.fetch_add(1, Ordering::SeqCst);
+}
+
+fn handler_b() {
+    RAN.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test]
+fn concurrent_register_and_unregister_never_tears() {
+    loom::model(|| {
+        RAN.store(0, Ordering::SeqCst);
+        let table = Arc::new(HandlerTable::<1>::new());
+
+        let t1 = {
+            let table = table.clone();
+            thread::spawn(move || table.register_handler(0, handler))
+        };
+        let t2 = {
+            let table = table.clone();
+            thread::spawn(move || table.unregister_handler(0))
+        };
+
+        let registered = t1.join().unwrap();
+        let unregistered = t2.join().unwrap();
+
+        // At most one of the two operations could have observed the slot in
+        // the state it required; either is a legal interleaving, but the
+        // handler must never be invoked with a torn function pointer.
+        if registered || unregistered.is_some() {
+            assert!(table.handle(0) || !table.handle(0));
+        }
+    });
+}
+
+#[test]
+fn concurrent_register_and_handle_sees_whole_or_nothing() {
+    loom::model(|| {
+        RAN.store(0, Ordering::SeqCst);
+        let table = Arc::new(HandlerTable::<1>::new());
+
+        let t1 = {
+            let table = table.clone();
+            thread::spawn(move || table.register_handler(0, handler))
+        };
+        let t2 = {
+            let table = table.clone();
+            thread::spawn(move || table.handle(0))
+        };
+
+        t1.join().unwrap();
+        t2.join().unwrap();
+
+        // The handler either ran zero or one times; a torn read of the
+        // function pointer would manifest as a crash rather than a count
+        // outside {0, 1}.
+        assert!(RAN.load(Ordering::SeqCst) <= 1);
+    });
+}
+
+#[test]
+fn concurrent_push_and_clear_never_invokes_a_freed_node() {
+    loom::model(|| {
+        RAN.store(0, Ordering::SeqCst);
+        let table = Arc::new(HandlerTable::<1>::new());
+        assert!(table.register_handler_with_priority(0, 0, handler));
+
+        let clearer = {
+            let table = table.clone();
+            thread::spawn(move || table.unregister_handler(0))
+        };
+        let invoker = {
+            let table = table.clone();
+            thread::spawn(move || table.handle_all())
+        };
+
+        clearer.join().unwrap();
+        let fired = invoker.join().unwrap();
+
+        // `invoke_all` traverses whatever chain it observed at the time: at
+        // most the one handler that was registered, never a node that
+        // `clear`'s reclamation already freed.
+        assert!(fired <= 1);
+        assert!(RAN.load(Ordering::SeqCst) <= 1);
+    });
+}
+
+#[test]
+fn concurrent_insert_never_double_claims_the_same_free_slot() {
+    loom::model(|| {
+        RAN.store(0, Ordering::SeqCst);
+        let table = Arc::new(HandlerTable::<2>::new());
+
+        let t1 = {
+            let table = table.clone();
+            thread::spawn(move || table.insert(handler_a))
+        };
+        let t2 = {
+            let table = table.clone();
+            thread::spawn(move || table.insert(handler_b))
+        };
+
+        let a = t1.join().unwrap();
+        let b = t2.join().unwrap();
+
+        // Both inserts must land in the table's two distinct slots; neither
+        // the free list nor the bump cursor may hand out the same index
+        // twice.
+        assert!(a.is_some());
+        assert!(b.is_some());
+        assert_ne!(a, b);
+    });
+}
+
+#[test]
+fn concurrent_insert_and_unregister_never_corrupt_the_free_list() {
+    loom::model(|| {
+        RAN.store(0, Ordering::SeqCst);
+        let table = Arc::new(HandlerTable::<3>::new());
+
+        // Occupy all three slots, then free 0 and 1 (in that order), so the
+        // free list reads head -> 1 -> 0 -> empty.
+        table.insert(handler_a).unwrap();
+        table.insert(handler_a).unwrap();
+        table.unregister_handler(0);
+        table.unregister_handler(1);
+
+        // `popper` and `churner` race to pop the free list's two entries.
+        // Whichever loses that race retries with a freshly re-read head, so
+        // by itself this wouldn't expose anything; the interesting schedule
+        // is the one where `popper` reads slot 1's (head, next) pair, then
+        // stalls before its CAS while `churner` fully: claims slot 1 itself,
+        // frees slot 2 (relinking head through it), and immediately frees
+        // its own slot 1 claim again. That last free re-publishes slot 1 as
+        // head with the *same* raw encoding `popper` already captured, but
+        // now linked to slot 2 instead of slot 0. An un-tagged CAS can't
+        // tell those two states apart, so `popper`'s stale compare succeeds
+        // and overwrites head with its originally-captured (now wrong, and
+        // already-claimed-by-nobody-anymore) next pointer, losing slot 2
+        // off the list entirely.
+        let popper = {
+            let table = table.clone();
+            thread::spawn(move || table.insert(handler_a))
+        };
+        let churner = {
+            let table = table.clone();
+            thread::spawn(move || {
+                let claimed = table.insert(handler_a);
+                table.unregister_handler(2);
+                if let Some(idx) = claimed {
+                    table.unregister_handler(idx);
+                }
+                claimed
+            })
+        };
+
+        popper.join().unwrap();
+        churner.join().unwrap();
+
+        // Whichever of the three slots `popper` ended up claiming, the
+        // other two are freed by the sequence above (one via `churner`'s
+        // first `insert` + final `unregister`, the other via `churner`'s
+        // `unregister_handler(2)`) and must both still be reachable from
+        // the free list: a lost slot here is exactly what an un-tagged CAS
+        // racing the schedule above would corrupt away.
+        assert!(table.insert(handler_a).is_some());
+        assert!(table.insert(handler_a).is_some());
+    });
+}
+
+#[test]
+fn concurrent_unregister_races_a_direct_reregister_without_corrupting_the_free_list() {
+    loom::model(|| {
+        RAN.store(0, Ordering::SeqCst);
+        let table = Arc::new(HandlerTable::<1>::new());
+        table.register_handler(0, handler_a);
+
+        let unregisterer = {
+            let table = table.clone();
+            thread::spawn(move || table.unregister_handler(0))
+        };
+        let churner = {
+            let table = table.clone();
+            thread::spawn(move || {
+                // A direct register + unregister pair on the same slot: if
+                // it lands while `unregisterer`'s own `push_free(0)` is
+                // still stuck retrying its CAS, this used to be able to
+                // race a *second* push of slot 0 onto the free list,
+                // splicing it into a cycle with itself (see the [chunk0-4]
+                // fix this guards against).
+                if table.register_handler(0, handler_b) {
+                    table.unregister_handler(0);
+                }
+            })
+        };
+
+        unregisterer.join().unwrap();
+        churner.join().unwrap();
+
+        // Slot 0 ends up vacant either way: `churner`'s `register_handler`
+        // either lost the race outright, or won it and then immediately
+        // unregistered its own registration again. A corrupted free list
+        // would make this `insert` spin forever instead of recycling the
+        // slot.
+        assert!(!table.is_occupied(0));
+        assert_eq!(table.insert(handler_a), Some(0));
+    });
+}
+
+static CHECKED_FIRED: AtomicUsize = AtomicUsize::new(0);
+
+fn fires_as_checked() {
+    CHECKED_FIRED.store(1, Ordering::SeqCst);
+}
+
+fn fires_as_churned() {
+    CHECKED_FIRED.store(2, Ordering::SeqCst);
+}
+
+#[test]
+fn register_checked_never_hands_back_a_handle_for_the_wrong_generation() {
+    loom::model(|| {
+        CHECKED_FIRED.store(0, Ordering::SeqCst);
+        let table = Arc::new(HandlerTable::<1>::new());
+        table.register_handler(0, fires_as_churned);
+
+        let checker = {
+            let table = table.clone();
+            thread::spawn(move || table.register_checked(0, fires_as_checked))
+        };
+        let churner = {
+            let table = table.clone();
+            thread::spawn(move || {
+                // Vacates and immediately re-occupies slot 0, racing
+                // `checker`'s own attempt to land in that same momentary
+                // vacancy. Snapshotting the generation either fully before
+                // or fully after `checker`'s registration CAS leaves a
+                // window where the returned `Handle` names a different
+                // occupant's generation than the one `checker` actually
+                // installed (see the [chunk0-1] fix this guards against).
+                table.unregister_handler(0);
+                table.register_handler(0, fires_as_churned);
+            })
+        };
+
+        let handle = checker.join().unwrap();
+        churner.join().unwrap();
+
+        if let Some(handle) = handle {
+            CHECKED_FIRED.store(0, Ordering::SeqCst);
+            if table.handle_token(handle) {
+                assert_eq!(
+                    CHECKED_FIRED.load(Ordering::SeqCst),
+                    1,
+                    "handle_token fired a handler other than the one register_checked installed"
+                );
+            } else {
+                // A rejected handle is only correct if `checker`'s own
+                // handler really isn't the slot's occupant any more.
+                CHECKED_FIRED.store(0, Ordering::SeqCst);
+                table.handle(0);
+                assert_ne!(
+                    CHECKED_FIRED.load(Ordering::SeqCst),
+                    1,
+                    "handle_token rejected a handle whose handler is still installed"
+                );
+            }
+        }
+    });
+}
+
+// `Chain` is `pub(crate)`, so it can be exercised directly here rather than
+// only indirectly through `HandlerTable`: this isolates the reclamation
+// scheme itself (push/clear/invoke_all racing) from the slot-table wrapping
+// logic that `concurrent_push_and_clear_never_invokes_a_freed_node` already
+// covers above.
+#[test]
+fn chain_concurrent_push_clear_and_invoke_never_invokes_a_freed_node() {
+    use crate::chain::Chain;
+
+    loom::model(|| {
+        RAN.store(0, Ordering::SeqCst);
+        let chain = Arc::new(Chain::new());
+        chain.push(0, handler);
+
+        let clearer = {
+            let chain = chain.clone();
+            thread::spawn(move || chain.clear())
+        };
+        let invoker = {
+            let chain = chain.clone();
+            thread::spawn(move || chain.invoke_all())
+        };
+
+        clearer.join().unwrap();
+        let fired = invoker.join().unwrap();
+
+        assert!(fired <= 1);
+        assert!(RAN.load(Ordering::SeqCst) <= 1);
+    });
+}
@@ -1,5 +1,7 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use handler_table::HandlerTable;
+use std::sync::Arc;
+use std::thread;
 
 static CALLED: AtomicBool = AtomicBool::new(false);
 
@@ -7,6 +9,40 @@ fn handler() {
     CALLED.store(true, Ordering::SeqCst);
 }
 
+static ORDER: AtomicUsize = AtomicUsize::new(0);
+static LOW_CALLED_AT: AtomicUsize = AtomicUsize::new(usize::MAX);
+static MID_CALLED_AT: AtomicUsize = AtomicUsize::new(usize::MAX);
+static HIGH_CALLED_AT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+fn low_priority_handler() {
+    LOW_CALLED_AT.store(ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+}
+
+fn mid_priority_handler() {
+    MID_CALLED_AT.store(ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+}
+
+fn high_priority_handler() {
+    HIGH_CALLED_AT.store(ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+}
+
+static TIE_ORDER: AtomicUsize = AtomicUsize::new(0);
+static TIE_FIRST_CALLED_AT: AtomicUsize = AtomicUsize::new(usize::MAX);
+static TIE_SECOND_CALLED_AT: AtomicUsize = AtomicUsize::new(usize::MAX);
+static TIE_THIRD_CALLED_AT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+fn tie_first_handler() {
+    TIE_FIRST_CALLED_AT.store(TIE_ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+}
+
+fn tie_second_handler() {
+    TIE_SECOND_CALLED_AT.store(TIE_ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+}
+
+fn tie_third_handler() {
+    TIE_THIRD_CALLED_AT.store(TIE_ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+}
+
 #[test]
 fn test_default() {
     let table = HandlerTable::<3>::default();
@@ -63,3 +99,234 @@ fn test_unregister_empty_slot() {
     // Unregistering from empty slot should return None
     assert!(table.unregister_handler(1).is_none());
 }
+
+#[test]
+fn test_register_checked_and_handle_token() {
+    CALLED.store(false, Ordering::SeqCst);
+    let table = HandlerTable::<4>::new();
+
+    let handle = table.register_checked(1, handler).expect("should register");
+    assert!(table.handle_token(handle));
+    assert!(CALLED.load(Ordering::SeqCst));
+
+    // Registering again on the same occupied slot fails.
+    assert!(table.register_checked(1, handler).is_none());
+}
+
+#[test]
+fn test_handle_token_rejects_stale_handle_after_reuse() {
+    CALLED.store(false, Ordering::SeqCst);
+    let table = HandlerTable::<4>::new();
+
+    let stale = table.register_checked(2, handler).expect("should register");
+    table.unregister_handler(2);
+
+    // A fresh registration in the same slot bumps the generation.
+    table.register_handler(2, handler);
+
+    // The old handle no longer matches the slot's generation.
+    assert!(!table.handle_token(stale));
+    assert!(!CALLED.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_register_handler_with_priority_fans_out_in_descending_order() {
+    ORDER.store(0, Ordering::SeqCst);
+    let table = HandlerTable::<2>::new();
+
+    assert!(table.register_handler_with_priority(0, 1, low_priority_handler));
+    assert!(table.register_handler_with_priority(0, 10, high_priority_handler));
+    assert!(table.register_handler_with_priority(0, 5, mid_priority_handler));
+
+    assert!(table.handle(0));
+
+    assert!(HIGH_CALLED_AT.load(Ordering::SeqCst) < MID_CALLED_AT.load(Ordering::SeqCst));
+    assert!(MID_CALLED_AT.load(Ordering::SeqCst) < LOW_CALLED_AT.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_register_handler_with_priority_same_priority_keeps_registration_order() {
+    TIE_ORDER.store(0, Ordering::SeqCst);
+    let table = HandlerTable::<1>::new();
+
+    assert!(table.register_handler_with_priority(0, 5, tie_first_handler));
+    assert!(table.register_handler_with_priority(0, 5, tie_second_handler));
+    assert!(table.register_handler_with_priority(0, 5, tie_third_handler));
+
+    assert!(table.handle(0));
+
+    assert!(TIE_FIRST_CALLED_AT.load(Ordering::SeqCst) < TIE_SECOND_CALLED_AT.load(Ordering::SeqCst));
+    assert!(TIE_SECOND_CALLED_AT.load(Ordering::SeqCst) < TIE_THIRD_CALLED_AT.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_register_handler_with_priority_coexists_with_register_handler() {
+    CALLED.store(false, Ordering::SeqCst);
+    let table = HandlerTable::<2>::new();
+
+    // A plain `register_handler` claims the slot...
+    assert!(table.register_handler(1, handler));
+    // ...but further handlers can still be fanned in via priority.
+    assert!(table.register_handler_with_priority(1, 5, handler));
+
+    assert!(table.handle(1));
+    assert!(CALLED.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_insert_claims_free_slots_and_reports_full() {
+    CALLED.store(false, Ordering::SeqCst);
+    let table = HandlerTable::<2>::new();
+
+    let a = table.insert(handler).expect("should claim a slot");
+    let b = table.insert(handler).expect("should claim a slot");
+    assert_ne!(a, b);
+
+    // Table is full now.
+    assert!(table.insert(handler).is_none());
+
+    assert!(table.handle(a));
+    assert!(CALLED.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_insert_recycles_unregistered_slot() {
+    CALLED.store(false, Ordering::SeqCst);
+    let table = HandlerTable::<1>::new();
+
+    let idx = table.insert(handler).expect("should claim the only slot");
+    assert!(table.insert(handler).is_none());
+
+    table.unregister_handler(idx);
+
+    // The freed slot is available again.
+    let recycled = table.insert(handler).expect("should recycle the freed slot");
+    assert_eq!(idx, recycled);
+}
+
+#[test]
+fn test_insert_skips_slots_claimed_directly_via_register_handler() {
+    CALLED.store(false, Ordering::SeqCst);
+    let table = HandlerTable::<6>::new();
+
+    // Claim slot 5 out from under the bump cursor before `insert` reaches it.
+    assert!(table.register_handler(5, handler));
+
+    let mut claimed = Vec::new();
+    for _ in 0..5 {
+        claimed.push(table.insert(handler).expect("should claim a free slot"));
+    }
+
+    // All 5 inserted handlers landed somewhere other than slot 5, and the
+    // table correctly reports full rather than silently dropping a handler.
+    assert!(!claimed.contains(&5));
+    assert!(table.insert(handler).is_none());
+    assert!(table.handle(5));
+}
+
+#[test]
+fn test_occupancy_tracks_register_and_unregister() {
+    let table = HandlerTable::<4>::new();
+    assert_eq!(table.occupied(), 0);
+    assert!(!table.is_occupied(1));
+
+    assert!(table.register_handler(1, handler));
+    assert!(table.is_occupied(1));
+    assert_eq!(table.occupied(), 1);
+
+    table.unregister_handler(1);
+    assert!(!table.is_occupied(1));
+    assert_eq!(table.occupied(), 0);
+}
+
+#[test]
+fn test_is_occupied_out_of_bounds_is_false() {
+    let table = HandlerTable::<2>::new();
+    assert!(!table.is_occupied(2));
+}
+
+#[test]
+fn test_handle_all_fires_every_occupied_slot_and_skips_empty_ones() {
+    CALLED.store(false, Ordering::SeqCst);
+    ORDER.store(0, Ordering::SeqCst);
+    let table = HandlerTable::<4>::new();
+
+    assert!(table.register_handler(0, low_priority_handler));
+    assert!(table.register_handler(2, mid_priority_handler));
+    // Slots 1 and 3 stay empty.
+
+    assert_eq!(table.handle_all(), 2);
+}
+
+static LAST_FIRED: AtomicUsize = AtomicUsize::new(0);
+
+fn checked_handler() {
+    LAST_FIRED.store(1, Ordering::SeqCst);
+}
+
+fn racing_handler() {
+    LAST_FIRED.store(2, Ordering::SeqCst);
+}
+
+#[test]
+fn test_register_checked_handle_never_fires_a_racing_replacement_handler() {
+    // Regression test: `register_checked` used to read the slot's generation
+    // only *after* its own registration already succeeded, leaving a window
+    // where a concurrent unregister+re-register could bump the generation
+    // first. The returned `Handle` would then carry the *next* occupant's
+    // generation, so `handle_token` would silently invoke that handler
+    // instead of correctly treating the handle as stale.
+    let table = Arc::new(HandlerTable::<1>::new());
+
+    let racer = {
+        let table = Arc::clone(&table);
+        thread::spawn(move || {
+            for _ in 0..20_000 {
+                table.unregister_handler(0);
+                table.register_handler(0, racing_handler);
+            }
+        })
+    };
+
+    for _ in 0..20_000 {
+        LAST_FIRED.store(0, Ordering::SeqCst);
+        if let Some(handle) = table.register_checked(0, checked_handler) {
+            if table.handle_token(handle) {
+                assert_eq!(
+                    LAST_FIRED.load(Ordering::SeqCst),
+                    1,
+                    "handle_token fired a handler other than the one this generation was issued for"
+                );
+            }
+            table.unregister_handler(0);
+        }
+    }
+
+    racer.join().unwrap();
+}
+
+#[test]
+fn test_handle_does_not_use_after_free_against_concurrent_unregister() {
+    // Regression test: `handle`/`handle_all` used to read `Chain` nodes that
+    // a racing `unregister_handler` could free out from under them. Spin
+    // both sides hard enough, for long enough, that a reclamation bug would
+    // reliably segfault rather than pass by luck.
+    let table = Arc::new(HandlerTable::<1>::new());
+    assert!(table.register_handler_with_priority(0, 0, low_priority_handler));
+
+    let churner = {
+        let table = Arc::clone(&table);
+        thread::spawn(move || {
+            for _ in 0..20_000 {
+                table.unregister_handler(0);
+                table.register_handler_with_priority(0, 0, low_priority_handler);
+            }
+        })
+    };
+
+    for _ in 0..20_000 {
+        table.handle(0);
+    }
+
+    churner.join().unwrap();
+}